@@ -3,7 +3,8 @@ use std::time::Instant;
 
 use gethostname::gethostname;
 use log::{debug, info, warn};
-use rumqttc::{AsyncClient, QoS};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::AsyncClient;
 use serde::{Deserialize, Serialize};
 use serde_json::{self};
 use shlex::{self};
@@ -25,7 +26,8 @@ struct DeviceInfo {
 struct DiscoveryInfo {
     name: String,
     unique_id: String,
-    command_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<String>,
     device: DeviceInfo,
     availability_topic: String,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -34,12 +36,87 @@ struct DiscoveryInfo {
     payload_press: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     entity_category: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_on: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_off: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    unit_of_measurement: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<String>,
+}
+
+/// What kind of Home Assistant entity an action shows up as, and how it is driven.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionKind {
+    /// A momentary `button` that runs `command` on every incoming message.
+    #[default]
+    Button,
+    /// A stateful `switch` driven by `command_on`/`command_off`.
+    Switch,
+    /// A `sensor` that runs `command` on an interval and publishes its stdout.
+    Sensor,
+}
+
+/// Default polling interval for `sensor` actions, in seconds.
+const DEFAULT_SENSOR_INTERVAL_SECS: u64 = 60;
+
+/// Payload Home Assistant sends/expects for a switch being turned on.
+const SWITCH_PAYLOAD_ON: &str = "ON";
+/// Payload Home Assistant sends/expects for a switch being turned off.
+const SWITCH_PAYLOAD_OFF: &str = "OFF";
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ResultSensorDiscoveryInfo {
+    name: String,
+    unique_id: String,
+    state_topic: String,
+    value_template: String,
+    device: DeviceInfo,
+    availability_topic: String,
+    entity_category: String,
+}
+
+/// Result of a single command execution, published to the result topic.
+#[derive(Serialize, Debug)]
+pub struct ExecutionResult {
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+    duration_ms: u128,
+}
+
+/// Maximum number of bytes of stdout/stderr kept in the published result.
+const MAX_RESULT_OUTPUT_LEN: usize = 4096;
+
+/// Truncate a byte slice to at most `MAX_RESULT_OUTPUT_LEN` bytes and turn it into a string.
+fn truncated_output(bytes: &[u8]) -> String {
+    let truncated = &bytes[..bytes.len().min(MAX_RESULT_OUTPUT_LEN)];
+    String::from_utf8_lossy(truncated).to_string()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Action {
     name: String,
-    command: String,
+    #[serde(default)]
+    kind: ActionKind,
+    /// Command to run. Used by `button` and `sensor` actions.
+    command: Option<String>,
+    /// Command to run when a `switch` action is turned on.
+    command_on: Option<String>,
+    /// Command to run when a `switch` action is turned off.
+    command_off: Option<String>,
+    /// Command that prints the current state of a `switch` action.
+    command_state: Option<String>,
+    /// Polling interval for a `sensor` action, in seconds. Defaults to 60.
+    interval_secs: Option<u64>,
+    /// Unit of measurement reported by a `sensor` action, e.g. `"%"` or `"°C"`.
+    unit_of_measurement: Option<String>,
+    /// Home Assistant device class for a `sensor` action, e.g. `"temperature"`.
+    device_class: Option<String>,
     icon: Option<String>,
     #[serde(skip)]
     instance_name: String,
@@ -48,13 +125,132 @@ pub struct Action {
 }
 
 impl Action {
-    /// Execute the command
-    pub async fn execute(&self) {
+    /// Execute the action for an incoming MQTT payload, publish the result to the
+    /// result topic and return it so callers (e.g. a v5 request/response reply) can
+    /// reuse it without re-running the command.
+    pub async fn execute(&self, payload: &[u8], client: &AsyncClient) -> Option<ExecutionResult> {
+        let result = match self.kind {
+            ActionKind::Button => {
+                let command = self.command.as_deref().unwrap_or_default();
+                self.run_command(command, payload).await
+            }
+            ActionKind::Switch => self.execute_switch(payload, client).await,
+            ActionKind::Sensor => {
+                // Sensors are polled on an interval by `run_sensor_loop` rather than
+                // dispatched from incoming publishes.
+                None
+            }
+        };
+
+        if let Some(result) = &result {
+            let payload = serde_json::to_string(result).unwrap();
+            if let Err(err) = client
+                .publish(self.result_topic(), QoS::AtLeastOnce, false, payload)
+                .await
+            {
+                warn!(
+                    "Unable to publish result to {}: {}",
+                    self.result_topic(),
+                    err
+                );
+            }
+        }
+
+        result
+    }
+
+    /// Run `command_on` or `command_off` depending on the incoming payload, then
+    /// publish the resulting state (from `command_state` if set, otherwise the
+    /// payload itself) to the state topic.
+    async fn execute_switch(
+        &self,
+        payload: &[u8],
+        client: &AsyncClient,
+    ) -> Option<ExecutionResult> {
+        let payload_str = String::from_utf8_lossy(payload);
+        let command = if payload_str.trim() == SWITCH_PAYLOAD_ON {
+            self.command_on.as_deref()
+        } else if payload_str.trim() == SWITCH_PAYLOAD_OFF {
+            self.command_off.as_deref()
+        } else {
+            // Not a payload we recognize: don't run anything, and don't overwrite the
+            // retained state with this unvalidated payload below.
+            warn!("Unknown switch payload for {}: {:?}", self, payload_str);
+            return None;
+        };
+
+        let result = match command {
+            Some(command) => self.run_command(command, payload).await,
+            None => None,
+        };
+
+        let state = match &self.command_state {
+            Some(command_state) => self
+                .run_command(command_state, payload)
+                .await
+                .map(|res| res.stdout.trim().to_string())
+                .unwrap_or_default(),
+            None => payload_str.trim().to_string(),
+        };
+
+        if let Err(err) = client
+            .publish(self.state_topic(), QoS::AtLeastOnce, true, state)
+            .await
+        {
+            warn!("Unable to publish state to {}: {}", self.state_topic(), err);
+        }
+
+        result
+    }
+
+    /// Poll `command` on `interval_secs` and publish its stdout to the state topic.
+    /// Intended to run for the lifetime of the bridge as its own task.
+    pub async fn run_sensor_loop(&self, client: &AsyncClient) {
+        let command = self.command.clone().unwrap_or_default();
+        let interval_secs = self.interval_secs.unwrap_or(DEFAULT_SENSOR_INTERVAL_SECS);
+        let mut ticker = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+
+        loop {
+            ticker.tick().await;
+
+            let state = match self.run_command(&command, &[]).await {
+                Some(result) => result.stdout.trim().to_string(),
+                None => continue,
+            };
+
+            if let Err(err) = client
+                .publish(self.state_topic(), QoS::AtLeastOnce, true, state)
+                .await
+            {
+                warn!("Unable to publish state to {}: {}", self.state_topic(), err);
+            }
+        }
+    }
+
+    /// Run a shell command, substituting `{{payload}}` with the incoming MQTT payload
+    /// and exposing it on stdin and as `MQTT_PAYLOAD`/`MQTT_TOPIC` environment variables.
+    async fn run_command(&self, command: &str, payload: &[u8]) -> Option<ExecutionResult> {
         info!("Executing {}", self);
-        debug!("Executing command: {}", self.command);
+        let payload_str = String::from_utf8_lossy(payload);
+        debug!("Executing command: {}", command);
         let start = Instant::now();
 
-        let splitted = shlex::split(self.command.as_str()).unwrap();
+        // Tokenize the configured command template first, then substitute the
+        // payload into the resulting argument(s). This keeps the payload as opaque
+        // argument content instead of feeding untrusted, network-controlled bytes
+        // into the shell lexer, so it can't inject extra arguments or change
+        // argument boundaries by containing whitespace or quotes.
+        let splitted = match shlex::split(command) {
+            Some(splitted) if !splitted.is_empty() => splitted,
+            _ => {
+                warn!("Empty or unparsable command for {}: {:?}", self, command);
+                return None;
+            }
+        };
+        let splitted: Vec<String> = splitted
+            .into_iter()
+            .map(|arg| arg.replace("{{payload}}", &payload_str))
+            .collect();
         let cmd = &splitted[0];
 
         let mut child = Command::new(cmd);
@@ -62,14 +258,48 @@ impl Action {
             let args = &splitted[1..];
             child.args(args);
         }
+        child
+            .env("MQTT_PAYLOAD", payload_str.as_ref())
+            .env("MQTT_TOPIC", self.command_topic())
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
 
-        match child.output().await {
-            Ok(out) => info!("Execution finished: {:?}", out),
-            Err(err) => warn!("Failed to execute: {}", err),
+        let result = match child.spawn() {
+            Ok(mut spawned) => {
+                if let Some(mut stdin) = spawned.stdin.take() {
+                    use tokio::io::AsyncWriteExt;
+                    let _ = stdin.write_all(payload).await;
+                }
+                match spawned.wait_with_output().await {
+                    Ok(out) => {
+                        info!("Execution finished: {:?}", out);
+                        Some(ExecutionResult {
+                            exit_code: out.status.code(),
+                            stdout: truncated_output(&out.stdout),
+                            stderr: truncated_output(&out.stderr),
+                            duration_ms: 0,
+                        })
+                    }
+                    Err(err) => {
+                        warn!("Failed to execute: {}", err);
+                        None
+                    }
+                }
+            }
+            Err(err) => {
+                warn!("Failed to spawn: {}", err);
+                None
+            }
         };
 
         let duration = start.elapsed();
         info!("Executing {} took {:?}", self, duration);
+
+        result.map(|mut result| {
+            result.duration_ms = duration.as_millis();
+            result
+        })
     }
 
     /// Return topic base for the instance.
@@ -84,15 +314,70 @@ impl Action {
         topic
     }
 
+    /// Return topic we publish execution results to.
+    fn result_topic(&self) -> String {
+        let topic = format!("{}/{}/result", self.topic_base(), self.unique_id());
+        topic
+    }
+
+    /// Return topic we publish switch or sensor state to.
+    fn state_topic(&self) -> String {
+        let topic = format!("{}/{}/state", self.topic_base(), self.unique_id());
+        topic
+    }
+
+    /// Topic that informs homeassistant about the result sensor.
+    fn result_discovery_topic(&self) -> String {
+        let topic = format!(
+            "homeassistant/sensor/{}/{}_result/config",
+            self.instance_name,
+            self.unique_id()
+        );
+        topic
+    }
+
+    /// Payload for homeassistant mqtt discovery of the result sensor.
+    fn result_discovery_payload(&self) -> String {
+        let name = gethostname().into_string().unwrap();
+        let identifiers = [name.clone()].to_vec();
+        let info = ResultSensorDiscoveryInfo {
+            name: format!("{} result", self.name),
+            unique_id: format!("{}_result", self.unique_id()),
+            availability_topic: self.availability_topic.to_string(),
+            state_topic: self.result_topic(),
+            value_template: "{{ value_json.exit_code }}".to_string(),
+            entity_category: "diagnostic".to_string(),
+            device: DeviceInfo {
+                name: format!("mqtt-bridge @ {}", name),
+                identifiers,
+                model: None,
+                manufacturer: None,
+            },
+        };
+        serde_json::to_string(&info).unwrap()
+    }
+
     /// Return slugified name.
     fn unique_id(&self) -> String {
         self.name.to_lowercase().replace(' ', "_")
     }
 
+    /// Whether this action is a `sensor`, i.e. polled on an interval instead of
+    /// reacting to incoming publishes.
+    pub fn is_sensor(&self) -> bool {
+        self.kind == ActionKind::Sensor
+    }
+
     /// Topic that informs homeassistant about its existence.
     fn discovery_topic(&self) -> String {
+        let component = match self.kind {
+            ActionKind::Button => "button",
+            ActionKind::Switch => "switch",
+            ActionKind::Sensor => "sensor",
+        };
         let topic = format!(
-            "homeassistant/button/{}/{}/config",
+            "homeassistant/{}/{}/{}/config",
+            component,
             self.instance_name,
             self.unique_id()
         );
@@ -103,14 +388,45 @@ impl Action {
     fn discovery_payload(&self) -> String {
         let name = gethostname().into_string().unwrap();
         let identifiers = [name.clone()].to_vec();
+        let (
+            command_topic,
+            state_topic,
+            payload_on,
+            payload_off,
+            unit_of_measurement,
+            device_class,
+        ) = match self.kind {
+            ActionKind::Button => (Some(self.command_topic()), None, None, None, None, None),
+            ActionKind::Switch => (
+                Some(self.command_topic()),
+                Some(self.state_topic()),
+                Some(SWITCH_PAYLOAD_ON.to_string()),
+                Some(SWITCH_PAYLOAD_OFF.to_string()),
+                None,
+                None,
+            ),
+            ActionKind::Sensor => (
+                None,
+                Some(self.state_topic()),
+                None,
+                None,
+                self.unit_of_measurement.clone(),
+                self.device_class.clone(),
+            ),
+        };
         let info = DiscoveryInfo {
             name: self.name.clone(),
             unique_id: self.unique_id(),
             availability_topic: self.availability_topic.to_string(),
-            command_topic: self.command_topic(),
+            command_topic,
             payload_press: None,
             entity_category: None,
             icon: self.icon.clone(),
+            state_topic,
+            payload_on,
+            payload_off,
+            unit_of_measurement,
+            device_class,
             device: DeviceInfo {
                 name: format!("mqtt-bridge @ {}", name),
                 identifiers,
@@ -129,14 +445,16 @@ impl Action {
         self.instance_name = config.mqtt.instance_name.clone();
         self.availability_topic = config.availability_topic();
 
-        // Subscribe to action topics
-        let sub = client
-            .subscribe(self.command_topic(), QoS::AtLeastOnce)
-            .await;
+        // Sensors are polled on an interval, there is no command topic to subscribe to.
+        if !self.is_sensor() {
+            let sub = client
+                .subscribe(self.command_topic(), QoS::AtLeastOnce)
+                .await;
 
-        match sub {
-            Ok(_res) => info!("Subscribed to {} for {}", self.command_topic(), self),
-            Err(err) => panic!("Unable to subscribe to {}: {}", self.command, err),
+            match sub {
+                Ok(_res) => info!("Subscribed to {} for {}", self.command_topic(), self),
+                Err(err) => panic!("Unable to subscribe to {}: {}", self.command_topic(), err),
+            }
         }
 
         // Publish discovery info
@@ -153,6 +471,34 @@ impl Action {
             Ok(_res) => info!("Published discovery info to {}", self.discovery_topic()),
             Err(err) => panic!("Unable to publish to {}: {}", self.discovery_topic(), err),
         }
+
+        // Sensors already publish their own state and don't go through execute(),
+        // so the generic result sensor would never see an update.
+        if self.is_sensor() {
+            return;
+        }
+
+        // Publish discovery info for the result sensor
+        let _result_pub = client
+            .publish(
+                self.result_discovery_topic(),
+                QoS::AtLeastOnce,
+                true,
+                self.result_discovery_payload(),
+            )
+            .await;
+
+        match _result_pub {
+            Ok(_res) => info!(
+                "Published result sensor discovery info to {}",
+                self.result_discovery_topic()
+            ),
+            Err(err) => panic!(
+                "Unable to publish to {}: {}",
+                self.result_discovery_topic(),
+                err
+            ),
+        }
     }
 }
 
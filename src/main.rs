@@ -2,10 +2,14 @@ use action::Action;
 
 use clap::{command, Parser};
 use gethostname::gethostname;
-use log::{debug, info, LevelFilter};
-use rumqttc::{AsyncClient, Event, EventLoop, Incoming, LastWill, MqttOptions, QoS};
+use log::{debug, info, warn, LevelFilter};
+use rumqttc::v5::mqttbytes::v5::{LastWill, PublishProperties};
+use rumqttc::v5::mqttbytes::{Event, Incoming, Outgoing, QoS};
+use rumqttc::v5::{AsyncClient, EventLoop, MqttOptions};
+use rumqttc::{TlsConfiguration, Transport};
 use serde::{Deserialize, Serialize};
 use serde_yaml::{self};
+use tokio::signal::unix::{signal, SignalKind};
 
 use std::collections::HashMap;
 use std::fmt::Debug;
@@ -18,17 +22,36 @@ mod action;
 #[derive(Serialize, Deserialize, Debug)]
 struct MqttConfig {
     host: String,
-    username: String,
-    password: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    tls: Option<TlsConfig>,
     #[serde(default = "get_hostname")]
     instance_name: String,
 }
 
+/// TLS transport configuration for talking to the broker over e.g. port 8883.
+#[derive(Serialize, Deserialize, Debug)]
+struct TlsConfig {
+    /// Path to the CA certificate used to verify the broker.
+    ca_cert: PathBuf,
+    /// Path to the client certificate, for mutual TLS.
+    client_cert: Option<PathBuf>,
+    /// Path to the client private key, for mutual TLS.
+    client_key: Option<PathBuf>,
+}
+
 // Current hostname as a string.
 fn get_hostname() -> String {
     gethostname().into_string().unwrap()
 }
 
+// Default, plaintext MQTT port.
+fn default_port() -> u16 {
+    1883
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Config {
     mqtt: MqttConfig,
@@ -61,17 +84,52 @@ struct Args {
     debug: u8,
 }
 
+/// Build rumqttc's TLS transport config, reading certificates and the optional
+/// client key/cert pair for mutual TLS from disk.
+fn build_tls_config(tls: &TlsConfig) -> TlsConfiguration {
+    let ca = std::fs::read(&tls.ca_cert).expect("Could not read CA certificate");
+
+    let client_auth = match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Some((
+            std::fs::read(cert).expect("Could not read client certificate"),
+            std::fs::read(key).expect("Could not read client key"),
+        )),
+        (None, None) => None,
+        _ => panic!("Both client_cert and client_key must be set for mutual TLS"),
+    };
+
+    TlsConfiguration::Simple {
+        ca,
+        alpn: None,
+        client_auth,
+    }
+}
+
 /// Perform mqtt connection establishment and setup for availability.
 async fn initialize_mqtt(config: &Config) -> (AsyncClient, EventLoop) {
     let mut opts = MqttOptions::new(
         format!("mqttbridge-{}", process::id()),
         config.mqtt.host.clone(),
-        1883,
+        config.mqtt.port,
     );
     opts.set_keep_alive(Duration::from_secs(5));
 
-    // TODO: handle optional username&password
-    opts.set_credentials(config.mqtt.username.clone(), config.mqtt.password.clone());
+    match (&config.mqtt.username, &config.mqtt.password) {
+        (Some(username), Some(password)) => {
+            opts.set_credentials(username.clone(), password.clone());
+        }
+        (None, None) => {}
+        _ => {
+            warn!("Only one of mqtt.username/mqtt.password is set, connecting without credentials")
+        }
+    }
+
+    // `Transport`/`TlsConfiguration` are defined at the rumqttc crate root and shared
+    // between the v4 and v5 `MqttOptions`, since the transport layer (TCP/TLS) is the
+    // same regardless of MQTT protocol version.
+    if let Some(tls) = &config.mqtt.tls {
+        opts.set_transport(Transport::tls_with_config(build_tls_config(tls)));
+    }
 
     // Set last will for the availability topic.
     opts.set_last_will(LastWill {
@@ -79,6 +137,7 @@ async fn initialize_mqtt(config: &Config) -> (AsyncClient, EventLoop) {
         message: "offline".into(),
         qos: QoS::AtLeastOnce,
         retain: true,
+        properties: None,
     });
 
     let (client, eventloop) = AsyncClient::new(opts, 10);
@@ -120,6 +179,16 @@ async fn initialize_actions(
         task.await;
     }
 
+    // Sensor actions poll their command on an interval instead of reacting to
+    // incoming publishes, so give each its own long-lived task.
+    for action in actions.iter().filter(|act| act.is_sensor()) {
+        let action = action.clone();
+        let client = client.clone();
+        tokio::spawn(async move {
+            action.run_sensor_loop(&client).await;
+        });
+    }
+
     // Create map (topic=>action) for lookups
     let command_topic_to_action: HashMap<String, Action> = actions
         .into_iter()
@@ -129,6 +198,45 @@ async fn initialize_actions(
     command_topic_to_action
 }
 
+/// Maximum number of event loop polls to wait for the offline publish and
+/// disconnect to actually reach the socket before giving up.
+const SHUTDOWN_FLUSH_POLLS: u32 = 10;
+
+/// Publish "offline" availability and disconnect cleanly.
+///
+/// `AsyncClient::publish`/`disconnect` only enqueue a request; the network write
+/// happens while polling `eventloop`. Keep polling until the disconnect has been
+/// sent (or the connection drops) so the packets are actually flushed instead of
+/// being queued and then discarded when the process exits.
+async fn shutdown(client: &AsyncClient, eventloop: &mut EventLoop, config: &Config) {
+    info!("Going offline, disconnecting.");
+
+    // TODO: handle errors
+    let _ = client
+        .publish(
+            config.availability_topic(),
+            QoS::AtLeastOnce,
+            true,
+            "offline".to_string(),
+        )
+        .await;
+
+    if let Err(err) = client.disconnect().await {
+        warn!("Error while disconnecting: {}", err);
+    }
+
+    for _ in 0..SHUTDOWN_FLUSH_POLLS {
+        match eventloop.poll().await {
+            Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => continue,
+            Err(err) => {
+                debug!("Event loop closed during shutdown flush: {}", err);
+                break;
+            }
+        }
+    }
+}
+
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
@@ -149,22 +257,70 @@ async fn main() {
     let (client, mut eventloop) = initialize_mqtt(&config).await;
     let topic_to_action = initialize_actions(&client, &config).await;
 
+    let mut sigterm = signal(SignalKind::terminate()).expect("Unable to install SIGTERM handler");
+
     info!("Init done, starting the listening loop.");
     loop {
-        while let Ok(notification) = eventloop.poll().await {
-            // We are interested only in the incoming pubs
-            if let Event::Incoming(Incoming::Publish(packet)) = notification {
-                debug!("Received on {}: {:?}", packet.topic, &packet.payload);
-
-                // Should be fine to unwrap w/o checking, as we are subscribed only to our own topics
-                // Clone is needed to allow passing copy of the action object to the spawn
-                let action = topic_to_action.get(&packet.topic).unwrap().clone();
-
-                // Spawn execute the wanted action,
-                // async move {} block is necessary to let the ownership change
-                tokio::spawn(async move {
-                    action.execute().await;
-                });
+        tokio::select! {
+            notification = eventloop.poll() => {
+                match notification {
+                    Ok(Event::Incoming(Incoming::Publish(packet))) => {
+                        let topic = String::from_utf8_lossy(&packet.topic).to_string();
+                        debug!("Received on {}: {:?}", topic, &packet.payload);
+
+                        // Should be fine to unwrap w/o checking, as we are subscribed only to our own topics
+                        // Clone is needed to allow passing copy of the action object to the spawn
+                        let action = topic_to_action.get(&topic).unwrap().clone();
+                        let payload = packet.payload.to_vec();
+                        let client = client.clone();
+                        // Correlation is carried entirely in the packet properties, no request state is kept.
+                        let properties = packet.properties.clone();
+
+                        // Spawn execute the wanted action,
+                        // async move {} block is necessary to let the ownership change
+                        tokio::spawn(async move {
+                            let result = action.execute(&payload, &client).await;
+
+                            // If the caller asked for a reply via v5 request/response properties,
+                            // echo the correlation data back alongside the result.
+                            if let Some(props) = properties {
+                                if let (Some(result), Some(response_topic)) =
+                                    (result, props.response_topic)
+                                {
+                                    let reply_properties = PublishProperties {
+                                        correlation_data: props.correlation_data,
+                                        ..Default::default()
+                                    };
+                                    let body = serde_json::to_string(&result).unwrap();
+                                    if let Err(err) = client
+                                        .publish_with_properties(
+                                            response_topic,
+                                            QoS::AtLeastOnce,
+                                            false,
+                                            body,
+                                            reply_properties,
+                                        )
+                                        .await
+                                    {
+                                        warn!("Unable to publish response: {}", err);
+                                    }
+                                }
+                            }
+                        });
+                    }
+                    Ok(_) => {}
+                    Err(err) => warn!("Connection error: {}", err),
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received SIGINT, shutting down.");
+                shutdown(&client, &mut eventloop, &config).await;
+                break;
+            }
+            _ = sigterm.recv() => {
+                info!("Received SIGTERM, shutting down.");
+                shutdown(&client, &mut eventloop, &config).await;
+                break;
             }
         }
     }